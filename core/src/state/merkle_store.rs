@@ -1,63 +1,415 @@
-use rocksdb::{Options, DB};
+use rocksdb::DB;
 use serde::{de::DeserializeOwned, Serialize};
 use serde_json::{from_slice, to_vec};
 use sparse_merkle_tree::error::Error;
+use sparse_merkle_tree::merge::MergeValue;
 use sparse_merkle_tree::traits::{StoreReadOps, StoreWriteOps};
 use sparse_merkle_tree::BranchKey;
 use sparse_merkle_tree::BranchNode;
 use sparse_merkle_tree::H256;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 
-//Store to be used inside StateMachine to store Merkle Tree.
-#[derive(Clone)]
-pub struct MerkleStore {
+fn version_meta_key() -> Vec<u8> {
+    b"meta/version".to_vec()
+}
+
+fn all_keys_meta_key() -> Vec<u8> {
+    b"meta/all_keys".to_vec()
+}
+
+fn root_key(version: u64) -> Vec<u8> {
+    let mut key = b"root/".to_vec();
+    key.extend_from_slice(&version.to_be_bytes());
+    key
+}
+
+fn hist_key(version: u64, node_key: &[u8]) -> Vec<u8> {
+    let mut key = b"hist/".to_vec();
+    key.extend_from_slice(&version.to_be_bytes());
+    key.extend_from_slice(node_key);
+    key
+}
+
+fn vidx_key(node_key: &[u8]) -> Vec<u8> {
+    let mut key = b"vidx/".to_vec();
+    key.extend_from_slice(node_key);
+    key
+}
+
+fn leaf_count_meta_key() -> Vec<u8> {
+    b"meta/leaf_count".to_vec()
+}
+
+fn root_version_key(root: &H256) -> Vec<u8> {
+    let mut key = b"rootver/".to_vec();
+    key.extend_from_slice(root.as_slice());
+    key
+}
+
+fn decode_be_u64(bytes: &[u8]) -> Option<u64> {
+    let buf: [u8; 8] = bytes.try_into().ok()?;
+    Some(u64::from_be_bytes(buf))
+}
+
+/// Identifies a frame in the nested checkpoint stack, returned by
+/// `MerkleStore::checkpoint` and consumed by `rollback`/`release`.
+pub type CheckpointId = usize;
+
+/// The checkpoint stack backing a `MerkleStore`'s cache: frame 0 is the base
+/// overlay, with one extra frame per open `checkpoint()`. Named so `Arc<Mutex<_>>`
+/// around it doesn't trip `clippy::type_complexity`.
+pub type CacheStack = Vec<HashMap<Vec<u8>, Vec<u8>>>;
+
+/// Height of the top-most branch node; leaves live at height 0.
+const TOP_HEIGHT: u8 = 255;
+
+fn h256_from_bytes(key: &[u8]) -> Option<H256> {
+    let bytes: [u8; 32] = key.try_into().ok()?;
+    Some(H256::from(bytes))
+}
+
+fn is_zero_merge_value(value: &MergeValue) -> bool {
+    matches!(value, MergeValue::Value(h) if h.is_zero())
+}
+
+/// The overlay that puts/deletes are applied to: the most recently opened
+/// checkpoint, or the base frame if none are open. Every `MerkleStore` cache
+/// stack is seeded with a base frame, so this never needs to push one.
+fn top_frame(cache: &mut [HashMap<Vec<u8>, Vec<u8>>]) -> &mut HashMap<Vec<u8>, Vec<u8>> {
+    cache.last_mut().expect("cache stack always has a base frame")
+}
+
+/// Minimal key-value interface `MerkleStore` needs from its underlying
+/// database. Implementing this trait lets the same table/merkle code in
+/// `MerkleStore` run against RocksDB in production or an in-memory map in
+/// tests, without ever touching disk.
+pub trait KvBackend: Send + Sync {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error>;
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), Error>;
+
+    fn delete(&self, key: &[u8]) -> Result<(), Error>;
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Error> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Apply a batch of puts/deletes as a single unit.
+    fn write(&self, batch: KvBatch) -> Result<(), Error>;
+}
+
+/// A set of puts/deletes to be applied to a `KvBackend` together.
+#[derive(Default)]
+pub struct KvBatch {
+    puts: Vec<(Vec<u8>, Vec<u8>)>,
+    deletes: Vec<Vec<u8>>,
+}
+
+impl KvBatch {
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.puts.push((key, value));
+    }
+
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.deletes.push(key);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.puts.is_empty() && self.deletes.is_empty()
+    }
+}
+
+/// RocksDB-backed `KvBackend`, the production driver.
+pub struct RocksDbBackend {
     db: Arc<Mutex<DB>>,
-    cache: Arc<Mutex<HashMap<Vec<u8>, Vec<u8>>>>,
+    /// Whether batched writes are fsync'd before `write` returns. Durable
+    /// but slower; off by default to match the previous unbatched writes.
+    sync: bool,
 }
 
-impl MerkleStore {
-    pub fn with_db(db: Arc<Mutex<DB>>, cache: Arc<Mutex<HashMap<Vec<u8>, Vec<u8>>>>) -> Self {
-        MerkleStore { db, cache }
+impl RocksDbBackend {
+    pub fn new(db: Arc<Mutex<DB>>) -> Self {
+        RocksDbBackend { db, sync: false }
+    }
+
+    pub fn with_sync(db: Arc<Mutex<DB>>, sync: bool) -> Self {
+        RocksDbBackend { db, sync }
+    }
+}
+
+impl KvBackend for RocksDbBackend {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let db = match self.db.lock() {
+            Ok(i) => i,
+            Err(_) => return Err(Error::Store(String::from("No lock obtained."))),
+        };
+
+        match db.get(key) {
+            Err(e) => Err(Error::Store(e.to_string())),
+            Ok(i) => Ok(i),
+        }
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        let db = match self.db.lock() {
+            Ok(i) => i,
+            Err(_) => return Err(Error::Store(String::from("No lock obtained."))),
+        };
+
+        match db.put(key, value) {
+            Err(e) => Err(Error::Store(e.to_string())),
+            Ok(()) => Ok(()),
+        }
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), Error> {
+        let db = match self.db.lock() {
+            Ok(i) => i,
+            Err(_) => return Err(Error::Store(String::from("No lock obtained."))),
+        };
+
+        match db.delete(key) {
+            Err(e) => Err(Error::Store(e.to_string())),
+            Ok(()) => Ok(()),
+        }
+    }
+
+    fn write(&self, batch: KvBatch) -> Result<(), Error> {
+        let db = match self.db.lock() {
+            Ok(i) => i,
+            Err(_) => return Err(Error::Store(String::from("No lock obtained."))),
+        };
+
+        let mut write_batch = rocksdb::WriteBatch::default();
+        for (key, value) in batch.puts {
+            write_batch.put(key, value);
+        }
+        for key in batch.deletes {
+            write_batch.delete(key);
+        }
+
+        let mut write_opts = rocksdb::WriteOptions::default();
+        write_opts.set_sync(self.sync);
+
+        match db.write_opt(write_batch, &write_opts) {
+            Err(e) => Err(Error::Store(e.to_string())),
+            Ok(()) => Ok(()),
+        }
+    }
+}
+
+/// Pure in-memory `KvBackend` backed by a `HashMap`, for tests that want to
+/// exercise `MerkleStore`/`StoreReadOps`/`StoreWriteOps` without disk I/O.
+#[derive(Default)]
+pub struct MemoryBackend {
+    map: Mutex<HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        MemoryBackend::default()
+    }
+}
+
+impl KvBackend for MemoryBackend {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let map = match self.map.lock() {
+            Ok(i) => i,
+            Err(_) => return Err(Error::Store(String::from("No lock obtained."))),
+        };
+
+        Ok(map.get(key).cloned())
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        let mut map = match self.map.lock() {
+            Ok(i) => i,
+            Err(_) => return Err(Error::Store(String::from("No lock obtained."))),
+        };
+
+        map.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), Error> {
+        let mut map = match self.map.lock() {
+            Ok(i) => i,
+            Err(_) => return Err(Error::Store(String::from("No lock obtained."))),
+        };
+
+        map.remove(key);
+        Ok(())
+    }
+
+    fn write(&self, batch: KvBatch) -> Result<(), Error> {
+        let mut map = match self.map.lock() {
+            Ok(i) => i,
+            Err(_) => return Err(Error::Store(String::from("No lock obtained."))),
+        };
+
+        for key in batch.deletes {
+            map.remove(&key);
+        }
+        for (key, value) in batch.puts {
+            map.insert(key, value);
+        }
+        Ok(())
+    }
+}
+
+/// Leaves that changed during a single `commit()`, or between two roots
+/// diffed with `MerkleStore::diff_against`. `added` carries the new,
+/// serialized leaf value alongside its key; `removed` only needs the key.
+#[derive(Debug, Default, Clone)]
+pub struct StateDiff {
+    pub added: Vec<(H256, Vec<u8>)>,
+    pub removed: Vec<H256>,
+}
+
+/// RocksDB-flavoured `MerkleStore`, an alias so call sites that only know
+/// about RocksDB don't need to name the backend.
+pub type RocksMerkleStore = MerkleStore<RocksDbBackend>;
+
+/// Read-only view over one retained version of a `B`-backed store, returned
+/// by `MerkleStore::open_at`. Reads of a node are redirected to the latest
+/// version index entry at or before the pinned version; writes are rejected
+/// since historical views must not mutate the live tree.
+pub struct HistoricalBackend<B: KvBackend> {
+    db: Arc<B>,
+    version: u64,
+}
+
+impl<B: KvBackend> KvBackend for HistoricalBackend<B> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let versions = match self.db.get(&vidx_key(key))? {
+            Some(bytes) => from_slice::<Vec<u64>>(&bytes).unwrap_or_default(),
+            None => return self.db.get(key),
+        };
+
+        match versions.into_iter().filter(|v| *v <= self.version).max() {
+            Some(version) => {
+                let value = self.db.get(&hist_key(version, key))?;
+                Ok(value.filter(|bytes| !bytes.is_empty()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn put(&self, _key: &[u8], _value: &[u8]) -> Result<(), Error> {
+        Err(Error::Store(String::from(
+            "historical store opened with open_at() is read-only",
+        )))
+    }
+
+    fn delete(&self, _key: &[u8]) -> Result<(), Error> {
+        Err(Error::Store(String::from(
+            "historical store opened with open_at() is read-only",
+        )))
+    }
+
+    fn write(&self, _batch: KvBatch) -> Result<(), Error> {
+        Err(Error::Store(String::from(
+            "historical store opened with open_at() is read-only",
+        )))
+    }
+}
+
+//Store to be used inside StateMachine to store Merkle Tree.
+pub struct MerkleStore<B: KvBackend> {
+    db: Arc<B>,
+    /// A stack of overlay maps sitting on top of the committed backend.
+    /// Frame 0 is the base overlay that `commit()` flushes; `checkpoint()`
+    /// pushes additional frames on top of it for speculative execution.
+    /// Reads fall through the stack top-to-bottom and finally to `db`; puts
+    /// and deletes only ever touch the top frame.
+    cache: Arc<Mutex<CacheStack>>,
+    /// Monotonically increasing commit counter, persisted alongside the
+    /// data so it survives process restarts. Doubles as the block height
+    /// tag used to retain and later look up historical roots.
+    version: Arc<Mutex<u64>>,
+}
+
+// Every field is an `Arc`, so cloning a `MerkleStore` never needs `B: Clone` —
+// derived `Clone` would add that bound and break `RocksMerkleStore::clone()`
+// since none of the shipped backends implement it.
+impl<B: KvBackend> Clone for MerkleStore<B> {
+    fn clone(&self) -> Self {
+        MerkleStore {
+            db: self.db.clone(),
+            cache: self.cache.clone(),
+            version: self.version.clone(),
+        }
+    }
+}
+
+impl<B: KvBackend> MerkleStore<B> {
+    pub fn with_db(db: Arc<B>, cache: Arc<Mutex<CacheStack>>) -> Self {
+        let version = match db.get(&version_meta_key()) {
+            Ok(Some(bytes)) => decode_be_u64(&bytes).unwrap_or(0),
+            _ => 0,
+        };
+
+        MerkleStore {
+            db,
+            cache,
+            version: Arc::new(Mutex::new(version)),
+        }
+    }
+
+    /// The version tag of the last successful `commit()`.
+    pub fn current_version(&self) -> Result<u64, Error> {
+        match self.version.lock() {
+            Ok(i) => Ok(*i),
+            Err(_) => Err(Error::Store(String::from("No lock obtained."))),
+        }
+    }
+
+    fn load_all_keys(&self) -> Result<HashSet<Vec<u8>>, Error> {
+        match self.db.get(&all_keys_meta_key())? {
+            Some(bytes) => Ok(from_slice(&bytes).unwrap_or_default()),
+            None => Ok(HashSet::new()),
+        }
+    }
+
+    fn load_version_index(&self, node_key: &[u8]) -> Result<Vec<u64>, Error> {
+        match self.db.get(&vidx_key(node_key))? {
+            Some(bytes) => Ok(from_slice(&bytes).unwrap_or_default()),
+            None => Ok(Vec::new()),
+        }
     }
 
     pub fn get<V: DeserializeOwned>(&self, serialized_key: &[u8]) -> Result<Option<V>, Error> {
         let cache = match self.cache.lock() {
             Ok(i) => i,
-            Err(e) => return Err(Error::Store(String::from("No lock obtained."))),
+            Err(_) => return Err(Error::Store(String::from("No lock obtained."))),
         };
 
-        match cache.get(serialized_key) {
-            Some(i) => {
+        for frame in cache.iter().rev() {
+            if let Some(i) = frame.get(serialized_key) {
                 //Empty vectors mean the value was deleted.
-                if !i.is_empty() {
-                    Ok(from_slice::<Option<V>>(&i).unwrap())
+                return if !i.is_empty() {
+                    Ok(from_slice::<Option<V>>(i).unwrap())
                 } else {
                     Ok(None)
-                }
-            }
-            None => {
-                let db = match self.db.lock() {
-                    Ok(i) => i,
-                    Err(e) => return Err(Error::Store(String::from("No lock obtained."))),
                 };
-
-                match db.get(serialized_key) {
-                    Err(e) => Err(Error::Store(e.to_string())),
-                    Ok(None) => Ok(None),
-                    Ok(Some(i)) => Ok(from_slice::<Option<V>>(&i).unwrap()),
-                }
             }
         }
+
+        match self.db.get(serialized_key)? {
+            None => Ok(None),
+            Some(i) => Ok(from_slice::<Option<V>>(&i).unwrap()),
+        }
     }
 
     pub fn put<V: Serialize>(&self, serialized_key: &[u8], value: &V) -> Result<(), Error> {
         let mut cache = match self.cache.lock() {
             Ok(i) => i,
-            Err(e) => return Err(Error::Store(String::from("No lock obtained."))),
+            Err(_) => return Err(Error::Store(String::from("No lock obtained."))),
         };
 
-        cache.insert(serialized_key.to_vec(), to_vec(value).unwrap());
+        let top = top_frame(&mut cache);
+        top.insert(serialized_key.to_vec(), to_vec(value).unwrap());
 
         Ok(())
     }
@@ -65,59 +417,466 @@ impl MerkleStore {
     pub fn delete(&self, serialized_key: &[u8]) -> Result<(), Error> {
         let mut cache = match self.cache.lock() {
             Ok(i) => i,
-            Err(e) => return Err(Error::Store(String::from("No lock obtained."))),
+            Err(_) => return Err(Error::Store(String::from("No lock obtained."))),
         };
 
-        cache.remove(&serialized_key.to_vec());
+        let top = top_frame(&mut cache);
+        top.insert(serialized_key.to_vec(), vec![]);
 
-        cache.insert(serialized_key.to_vec(), vec![]);
+        Ok(())
+    }
+
+    /// Snapshot the current overlay and start a new one on top of it. Puts
+    /// and deletes made after this call are isolated in the new frame until
+    /// it is `release`d into the frame below or `rollback`ed away entirely —
+    /// giving per-transaction atomicity inside a block without a full DB
+    /// round-trip per transaction.
+    pub fn checkpoint(&self) -> Result<CheckpointId, Error> {
+        let mut cache = match self.cache.lock() {
+            Ok(i) => i,
+            Err(_) => return Err(Error::Store(String::from("No lock obtained."))),
+        };
+
+        cache.push(HashMap::new());
+        Ok(cache.len() - 1)
+    }
+
+    /// Discard every put/delete made since `checkpoint_id` was taken.
+    pub fn rollback(&self, checkpoint_id: CheckpointId) -> Result<(), Error> {
+        let mut cache = match self.cache.lock() {
+            Ok(i) => i,
+            Err(_) => return Err(Error::Store(String::from("No lock obtained."))),
+        };
+
+        if checkpoint_id == 0 || checkpoint_id >= cache.len() {
+            return Err(Error::Store(String::from("unknown checkpoint id")));
+        }
 
+        cache.truncate(checkpoint_id);
         Ok(())
     }
 
-    pub fn commit(&mut self) -> Result<(), Error> {
-        let db = match self.db.lock() {
+    /// Fold every frame opened since `checkpoint_id` into the frame below it,
+    /// keeping the writes without flushing them to the backend.
+    pub fn release(&self, checkpoint_id: CheckpointId) -> Result<(), Error> {
+        let mut cache = match self.cache.lock() {
             Ok(i) => i,
-            Err(e) => return Err(Error::Store(String::from("No lock obtained."))),
+            Err(_) => return Err(Error::Store(String::from("No lock obtained."))),
         };
+
+        if checkpoint_id == 0 || checkpoint_id >= cache.len() {
+            return Err(Error::Store(String::from("unknown checkpoint id")));
+        }
+
+        let released = cache.split_off(checkpoint_id);
+        let target = top_frame(&mut cache);
+        for frame in released {
+            target.extend(frame);
+        }
+
+        Ok(())
+    }
+
+    /// Flush the cache to the backend as a single atomic write, returning the
+    /// leaves that changed. Building one `KvBatch` and applying it in one
+    /// `write` call (rather than issuing a `put`/`delete` per cached key)
+    /// means a crash mid-flush can never leave the on-disk tree half-written,
+    /// and the cache is only cleared once the batch has actually landed.
+    ///
+    /// `root` is the tree's root hash after this commit (the caller — the
+    /// `SparseMerkleTree` wrapping this store — is the one that knows it).
+    /// It is tagged with the new version so `get_root`/`open_at` can later
+    /// recover this exact state.
+    pub fn commit(&mut self, root: H256) -> Result<StateDiff, Error> {
         let mut cache = match self.cache.lock() {
             Ok(i) => i,
-            Err(e) => return Err(Error::Store(String::from("No lock obtained."))),
+            Err(_) => return Err(Error::Store(String::from("No lock obtained."))),
         };
 
-        for (key, value) in cache.iter() {
+        // Any still-open checkpoints are folded in too, so a commit always
+        // flushes the full speculative state rather than just the base.
+        let mut flattened = HashMap::new();
+        for frame in cache.iter() {
+            flattened.extend(frame.clone());
+        }
+
+        // The stored counter only moves once the batch below has been
+        // written, so a failed commit leaves `current_version()` unchanged.
+        let mut version_guard = match self.version.lock() {
+            Ok(i) => i,
+            Err(_) => return Err(Error::Store(String::from("No lock obtained."))),
+        };
+        let version = *version_guard + 1;
+
+        let mut all_keys = self.load_all_keys()?;
+        let mut batch = KvBatch::default();
+        let mut diff = StateDiff::default();
+        // Net change in leaf count, computed against what was already
+        // committed to `db` rather than the overlay: a key that was only
+        // ever inserted-then-deleted within the uncommitted cache must not
+        // move the counter at all, even though it never reached the DB.
+        let mut leaf_count_delta: i64 = 0;
+        for (key, value) in flattened.iter() {
+            let is_leaf_key = h256_from_bytes(key).is_some();
+            let existed_before = is_leaf_key && self.db.contains(key)?;
+
             if !value.is_empty() {
-                match db.put(key, value) {
-                    Err(e) => return Err(Error::Store(e.to_string())),
-                    _ => (),
+                batch.put(key.clone(), value.clone());
+                if let Some(leaf_key) = h256_from_bytes(key) {
+                    diff.added.push((leaf_key, value.clone()));
+                }
+                if is_leaf_key && !existed_before {
+                    leaf_count_delta += 1;
                 }
             } else {
-                match db.get(key) {
-                    Err(e) => return Err(Error::Store(e.to_string())),
-                    Ok(Some(_)) => match db.delete(key) {
-                        Err(e) => return Err(Error::Store(e.to_string())),
-                        _ => (),
-                    },
-                    Ok(None) => (),
+                batch.delete(key.clone());
+                if let Some(leaf_key) = h256_from_bytes(key) {
+                    diff.removed.push(leaf_key);
+                }
+                if is_leaf_key && existed_before {
+                    leaf_count_delta -= 1;
+                }
+            }
+
+            // Retain this version of the node (a tombstone for deletes) so
+            // `open_at` can still resolve it for any root up to and
+            // including this one, even once the live key moves on.
+            batch.put(hist_key(version, key), value.clone());
+
+            let mut versions = self.load_version_index(key)?;
+            if versions.last() != Some(&version) {
+                versions.push(version);
+                batch.put(vidx_key(key), to_vec(&versions).unwrap());
+            }
+
+            all_keys.insert(key.clone());
+        }
+
+        let leaf_count = self
+            .db
+            .get(&leaf_count_meta_key())?
+            .and_then(|bytes| decode_be_u64(&bytes))
+            .unwrap_or(0);
+        let leaf_count = (leaf_count as i64 + leaf_count_delta).max(0) as u64;
+        batch.put(leaf_count_meta_key(), leaf_count.to_be_bytes().to_vec());
+
+        // The counter is written directly rather than through the cached
+        // put/delete path above, so it needs its own version index/history
+        // entry — otherwise `open_at(old_version).len()` would fall through
+        // `HistoricalBackend::get`'s no-vidx case and read the live counter.
+        batch.put(
+            hist_key(version, &leaf_count_meta_key()),
+            leaf_count.to_be_bytes().to_vec(),
+        );
+        let mut leaf_count_versions = self.load_version_index(&leaf_count_meta_key())?;
+        if leaf_count_versions.last() != Some(&version) {
+            leaf_count_versions.push(version);
+            batch.put(
+                vidx_key(&leaf_count_meta_key()),
+                to_vec(&leaf_count_versions).unwrap(),
+            );
+        }
+        // So `prune`'s all_keys sweep reclaims old leaf-count snapshots too,
+        // the same way it does for every other versioned key.
+        all_keys.insert(leaf_count_meta_key());
+
+        batch.put(all_keys_meta_key(), to_vec(&all_keys).unwrap());
+        batch.put(root_key(version), root.as_slice().to_vec());
+        batch.put(root_version_key(&root), version.to_be_bytes().to_vec());
+        batch.put(version_meta_key(), version.to_be_bytes().to_vec());
+
+        self.db.write(batch)?;
+        *version_guard = version;
+
+        *cache = vec![HashMap::new()];
+        Ok(diff)
+    }
+
+    /// Number of leaves (NFTs) currently in the tree, tracked as a counter
+    /// folded into every `commit()` so reading it never has to walk the
+    /// tree.
+    pub fn len(&self) -> Result<u64, Error> {
+        Ok(self
+            .db
+            .get(&leaf_count_meta_key())?
+            .and_then(|bytes| decode_be_u64(&bytes))
+            .unwrap_or(0))
+    }
+
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Look up the root that was committed at `version`.
+    pub fn get_root(&self, version: u64) -> Result<Option<H256>, Error> {
+        match self.db.get(&root_key(version))? {
+            Some(bytes) => Ok(h256_from_bytes(&bytes)),
+            None => Ok(None),
+        }
+    }
+
+    /// The version a previously committed `root` was tagged with, used by
+    /// `diff_against` to resolve the "other" side of a diff to a point in
+    /// history rather than the live tree.
+    fn version_for_root(&self, root: H256) -> Result<Option<u64>, Error> {
+        match self.db.get(&root_version_key(&root))? {
+            Some(bytes) => Ok(decode_be_u64(&bytes)),
+            None => Ok(None),
+        }
+    }
+
+    /// A read-only `MerkleStore` view of the tree as it looked right after
+    /// `version` was committed, for generating proofs against past state.
+    pub fn open_at(&self, version: u64) -> MerkleStore<HistoricalBackend<B>> {
+        MerkleStore {
+            db: Arc::new(HistoricalBackend {
+                db: self.db.clone(),
+                version,
+            }),
+            cache: Arc::new(Mutex::new(vec![HashMap::new()])),
+            version: Arc::new(Mutex::new(version)),
+        }
+    }
+
+    /// Garbage-collect retained node versions, roots, and leaf-count history
+    /// that no longer fall within the last `keep_last_n` committed roots.
+    /// Each node's version index acts as a reference count across roots: a
+    /// stored `(version, node_key)` is only dropped once no retained root's
+    /// lookup would ever resolve to it. The live tree always needs at least
+    /// its own tip retained, so `keep_last_n == 0` is clamped to `1` — it
+    /// does not mean "keep nothing".
+    pub fn prune(&self, keep_last_n: u64) -> Result<usize, Error> {
+        let keep_last_n = keep_last_n.max(1);
+        let latest = self.current_version()?;
+        let min_retained = latest.saturating_sub(keep_last_n - 1);
+
+        let all_keys = self.load_all_keys()?;
+        let mut batch = KvBatch::default();
+        let mut removed = 0usize;
+
+        for key in &all_keys {
+            let versions = self.load_version_index(key)?;
+            if versions.is_empty() {
+                continue;
+            }
+
+            let mut kept = Vec::with_capacity(versions.len());
+            for (i, version) in versions.iter().enumerate() {
+                // This node's copy is live for every root version in
+                // [version, next_version - 1]; it is only safe to drop once
+                // that whole range falls before the retained window.
+                let range_end = versions.get(i + 1).map_or(latest, |next| next - 1);
+                if range_end >= min_retained {
+                    kept.push(*version);
+                } else {
+                    batch.delete(hist_key(*version, key));
+                    removed += 1;
+                }
+            }
+
+            if kept.len() != versions.len() {
+                batch.put(vidx_key(key), to_vec(&kept).unwrap());
+            }
+        }
+
+        // root_key/root_version_key aren't part of the versioned hist_key/
+        // vidx scheme above (they're keyed by version/root directly, one
+        // entry each, not a history per node) so they need their own sweep:
+        // any version below the retained window may have lost some of its
+        // nodes once the loop above runs, so its root must stop resolving
+        // too — otherwise `get_root`/`diff_against` would keep pointing at
+        // state that's actually gone. A root hash that was committed again
+        // at a retained version keeps its root-to-version entry.
+        for version in 1..min_retained {
+            if let Some(root) = self.get_root(version)? {
+                batch.delete(root_key(version));
+                if self.version_for_root(root)? == Some(version) {
+                    batch.delete(root_version_key(&root));
+                }
+                removed += 1;
+            }
+        }
+
+        if !batch.is_empty() {
+            self.db.write(batch)?;
+        }
+
+        Ok(removed)
+    }
+
+    /// Fetch a branch as it stood at `version`, via the same hist_key/vidx
+    /// mechanism `HistoricalBackend` uses, rather than the live branch
+    /// record — the live record reflects the *current* tree, which for an
+    /// older `other_root` is generally a different subtree entirely.
+    fn get_historical_branch(
+        &self,
+        branch_key: &BranchKey,
+        version: u64,
+    ) -> Result<Option<BranchNode>, Error> {
+        let serialized_key = match to_vec(branch_key) {
+            Err(e) => return Err(Error::Store(e.to_string())),
+            Ok(i) => i,
+        };
+
+        let historical = HistoricalBackend {
+            db: self.db.clone(),
+            version,
+        };
+
+        match historical.get(&serialized_key)? {
+            Some(bytes) => Ok(from_slice(&bytes).ok()),
+            None => Ok(None),
+        }
+    }
+
+    /// Diff this store's live tree (its cache over the committed backend),
+    /// whose root is `current_root`, against an older `other_root`. The
+    /// current side is always read from the live tree, so `current_root`
+    /// has to be the live root. The diff descends only into branches whose
+    /// hashes disagree and skips
+    /// identical subtrees entirely. This costs O(changed) rather than a full
+    /// scan, which is what a light client or proof driver needs to sync a
+    /// delta. Returns `Err` if `other_root` isn't a retained committed root,
+    /// or if any node needed to complete the diff is no longer reachable in
+    /// the backend (for example because `prune` dropped it) — a light client
+    /// or proof driver needs to know a delta is incomplete, not silently
+    /// receive one.
+    pub fn diff_against(&self, current_root: H256, other_root: H256) -> Result<StateDiff, Error> {
+        let other_version = self.version_for_root(other_root)?.ok_or_else(|| {
+            Error::Store(String::from(
+                "diff_against: other_root is not a retained committed root",
+            ))
+        })?;
+
+        let mut diff = StateDiff::default();
+        self.diff_node(
+            TOP_HEIGHT,
+            H256::zero(),
+            MergeValue::from_h256(current_root),
+            MergeValue::from_h256(other_root),
+            other_version,
+            &mut diff,
+        )?;
+        Ok(diff)
+    }
+
+    fn diff_node(
+        &self,
+        height: u8,
+        node_key: H256,
+        current: MergeValue,
+        other: MergeValue,
+        other_version: u64,
+        diff: &mut StateDiff,
+    ) -> Result<(), Error> {
+        if current == other {
+            return Ok(());
+        }
+
+        if height == 0 {
+            if !is_zero_merge_value(&current) {
+                // The leaf's serialized bytes as stored, the same form
+                // `commit()` reports in `added`.
+                let cached = match self.cache.lock() {
+                    Ok(cache) => cache
+                        .iter()
+                        .rev()
+                        .find_map(|frame| frame.get(node_key.as_slice()).cloned()),
+                    Err(_) => return Err(Error::Store(String::from("No lock obtained."))),
                 };
+                let value = match cached {
+                    Some(bytes) => Some(bytes).filter(|bytes| !bytes.is_empty()),
+                    None => self.db.get(node_key.as_slice())?,
+                };
+                match value {
+                    Some(value) => diff.added.push((node_key, value)),
+                    None => {
+                        return Err(Error::Store(String::from(
+                            "diff_against: missing current leaf",
+                        )))
+                    }
+                }
             }
+            if !is_zero_merge_value(&other) {
+                diff.removed.push(node_key);
+            }
+            return Ok(());
         }
 
-        cache.clear();
+        let branch_key = BranchKey { height, node_key };
+
+        // A non-zero side that has no branch record isn't an empty subtree —
+        // it's data the backend no longer has (e.g. pruned away). Reporting
+        // it as empty would make the diff silently wrong rather than failing
+        // loudly, so treat it as an error instead of defaulting to zero.
+        let (current_left, current_right) = if is_zero_merge_value(&current) {
+            (
+                MergeValue::from_h256(H256::zero()),
+                MergeValue::from_h256(H256::zero()),
+            )
+        } else {
+            match <Self as StoreReadOps<Vec<u8>>>::get_branch(self, &branch_key)? {
+                Some(b) => (b.left, b.right),
+                None => {
+                    return Err(Error::Store(format!(
+                        "diff_against: missing current branch at height {height}"
+                    )))
+                }
+            }
+        };
+        let (other_left, other_right) = if is_zero_merge_value(&other) {
+            (
+                MergeValue::from_h256(H256::zero()),
+                MergeValue::from_h256(H256::zero()),
+            )
+        } else {
+            match self.get_historical_branch(&branch_key, other_version)? {
+                Some(b) => (b.left, b.right),
+                None => {
+                    return Err(Error::Store(format!(
+                        "diff_against: missing historical branch at height {height} as of version {other_version}; it may have been pruned"
+                    )))
+                }
+            }
+        };
+
+        let mut left_key = node_key;
+        left_key.clear_bit(height - 1);
+        let mut right_key = node_key;
+        right_key.set_bit(height - 1);
+
+        self.diff_node(
+            height - 1,
+            left_key,
+            current_left,
+            other_left,
+            other_version,
+            diff,
+        )?;
+        self.diff_node(
+            height - 1,
+            right_key,
+            current_right,
+            other_right,
+            other_version,
+            diff,
+        )?;
+
         Ok(())
     }
 
     pub fn clear_cache(&mut self) -> Result<(), Error> {
         let mut cache = match self.cache.lock() {
             Ok(i) => i,
-            Err(e) => return Err(Error::Store(String::from("No lock obtained."))),
+            Err(_) => return Err(Error::Store(String::from("No lock obtained."))),
         };
 
-        Ok(cache.clear())
+        *cache = vec![HashMap::new()];
+        Ok(())
     }
 }
 
-impl<V: DeserializeOwned> StoreReadOps<V> for MerkleStore {
+impl<B: KvBackend, V: DeserializeOwned> StoreReadOps<V> for MerkleStore<B> {
     fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>, Error> {
         let serialized_key = match to_vec(branch_key) {
             Err(e) => return Err(Error::Store(e.to_string())),
@@ -134,7 +893,7 @@ impl<V: DeserializeOwned> StoreReadOps<V> for MerkleStore {
     }
 }
 
-impl<V: Serialize> StoreWriteOps<V> for MerkleStore {
+impl<B: KvBackend, V: Serialize> StoreWriteOps<V> for MerkleStore<B> {
     fn insert_branch(&mut self, node_key: BranchKey, branch: BranchNode) -> Result<(), Error> {
         let serialized_key = match to_vec(&node_key) {
             Err(e) => return Err(Error::Store(e.to_string())),
@@ -163,3 +922,181 @@ impl<V: Serialize> StoreWriteOps<V> for MerkleStore {
         self.delete(serialized_key)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_store() -> MerkleStore<MemoryBackend> {
+        MerkleStore::with_db(
+            Arc::new(MemoryBackend::new()),
+            Arc::new(Mutex::new(vec![HashMap::new()])),
+        )
+    }
+
+    fn h256_byte(b: u8) -> H256 {
+        let mut bytes = [0u8; 32];
+        bytes[0] = b;
+        H256::from(bytes)
+    }
+
+    #[test]
+    fn diff_against_reports_a_changed_leaf_value() {
+        let mut store = new_store();
+        let leaf_key = H256::zero();
+        let root_v1 = h256_byte(1);
+        let root_v2 = h256_byte(2);
+
+        // Seed a single-leaf path: every height's branch keeps the leaf's
+        // subtree on the left with an empty right sibling, so the traversal
+        // stays on node_key == H256::zero() all the way down to the leaf.
+        for height in 1..=TOP_HEIGHT {
+            StoreWriteOps::<Vec<u8>>::insert_branch(
+                &mut store,
+                BranchKey {
+                    height,
+                    node_key: H256::zero(),
+                },
+                BranchNode {
+                    left: MergeValue::from_h256(root_v1),
+                    right: MergeValue::from_h256(H256::zero()),
+                },
+            )
+            .unwrap();
+        }
+        StoreWriteOps::<Vec<u8>>::insert_leaf(&mut store, leaf_key, b"old".to_vec()).unwrap();
+        store.commit(root_v1).unwrap();
+
+        // Change the leaf's value; every branch along its path gets a new
+        // (fabricated) hash, the way a real value update would.
+        for height in 1..=TOP_HEIGHT {
+            StoreWriteOps::<Vec<u8>>::insert_branch(
+                &mut store,
+                BranchKey {
+                    height,
+                    node_key: H256::zero(),
+                },
+                BranchNode {
+                    left: MergeValue::from_h256(root_v2),
+                    right: MergeValue::from_h256(H256::zero()),
+                },
+            )
+            .unwrap();
+        }
+        StoreWriteOps::<Vec<u8>>::insert_leaf(&mut store, leaf_key, b"new".to_vec()).unwrap();
+        store.commit(root_v2).unwrap();
+
+        let diff = store.diff_against(root_v2, root_v1).unwrap();
+        assert_eq!(
+            diff.added,
+            vec![(leaf_key, to_vec(&b"new".to_vec()).unwrap())]
+        );
+        assert_eq!(diff.removed, vec![leaf_key]);
+    }
+
+    /// Commits a single-leaf version following the same path-seeding pattern
+    /// as `diff_against_reports_a_changed_leaf_value`, for tests that need
+    /// several versions in a row.
+    fn commit_single_leaf_version(
+        store: &mut MerkleStore<MemoryBackend>,
+        leaf_key: H256,
+        root: H256,
+        value: &[u8],
+    ) {
+        for height in 1..=TOP_HEIGHT {
+            StoreWriteOps::<Vec<u8>>::insert_branch(
+                store,
+                BranchKey {
+                    height,
+                    node_key: H256::zero(),
+                },
+                BranchNode {
+                    left: MergeValue::from_h256(root),
+                    right: MergeValue::from_h256(H256::zero()),
+                },
+            )
+            .unwrap();
+        }
+        StoreWriteOps::<Vec<u8>>::insert_leaf(store, leaf_key, value.to_vec()).unwrap();
+        store.commit(root).unwrap();
+    }
+
+    #[test]
+    fn prune_reclaims_old_roots_and_diff_against_errors_on_pruned_data() {
+        let mut store = new_store();
+        let leaf_key = H256::zero();
+        let root_v1 = h256_byte(1);
+        let root_v2 = h256_byte(2);
+        let root_v3 = h256_byte(3);
+
+        commit_single_leaf_version(&mut store, leaf_key, root_v1, b"v1");
+        commit_single_leaf_version(&mut store, leaf_key, root_v2, b"v2");
+        commit_single_leaf_version(&mut store, leaf_key, root_v3, b"v3");
+
+        store.prune(1).unwrap();
+
+        // Only the latest version's root is still within the retained
+        // window; the older ones must stop resolving rather than pointing
+        // at state whose backing nodes are already gone.
+        assert_eq!(store.get_root(3).unwrap(), Some(root_v3));
+        assert_eq!(store.get_root(2).unwrap(), None);
+        assert_eq!(store.get_root(1).unwrap(), None);
+
+        let historical = store.open_at(3);
+        assert_eq!(
+            historical.get::<Vec<u8>>(leaf_key.as_slice()).unwrap(),
+            Some(b"v3".to_vec())
+        );
+
+        // Diffing against a pruned root must fail loudly, not come back
+        // with a silently incomplete result.
+        assert!(store.diff_against(root_v3, root_v1).is_err());
+    }
+
+    #[test]
+    fn nested_checkpoints_rollback_and_release() {
+        let mut store = new_store();
+        let key = b"k".to_vec();
+
+        store.put(&key, &"a".to_string()).unwrap();
+
+        let cp1 = store.checkpoint().unwrap();
+        store.put(&key, &"b".to_string()).unwrap();
+
+        let cp2 = store.checkpoint().unwrap();
+        store.put(&key, &"c".to_string()).unwrap();
+        assert_eq!(store.get::<String>(&key).unwrap(), Some("c".to_string()));
+
+        store.rollback(cp2).unwrap();
+        assert_eq!(store.get::<String>(&key).unwrap(), Some("b".to_string()));
+
+        store.release(cp1).unwrap();
+        assert_eq!(store.get::<String>(&key).unwrap(), Some("b".to_string()));
+
+        store.commit(h256_byte(1)).unwrap();
+        assert_eq!(store.get::<String>(&key).unwrap(), Some("b".to_string()));
+    }
+
+    #[test]
+    fn leaf_count_tracks_net_inserts_and_deletes_across_commits() {
+        let mut store = new_store();
+        let k1 = h256_byte(1);
+        let k2 = h256_byte(2);
+
+        StoreWriteOps::<Vec<u8>>::insert_leaf(&mut store, k1, b"a".to_vec()).unwrap();
+        store.commit(h256_byte(100)).unwrap();
+        assert_eq!(store.len().unwrap(), 1);
+
+        // Insert then delete the same key before it's ever committed: the
+        // net change across this commit must be zero even though the key
+        // never reaches the backend.
+        StoreWriteOps::<Vec<u8>>::insert_leaf(&mut store, k2, b"b".to_vec()).unwrap();
+        StoreWriteOps::<Vec<u8>>::remove_leaf(&mut store, &k2).unwrap();
+        store.commit(h256_byte(101)).unwrap();
+        assert_eq!(store.len().unwrap(), 1);
+
+        StoreWriteOps::<Vec<u8>>::remove_leaf(&mut store, &k1).unwrap();
+        store.commit(h256_byte(102)).unwrap();
+        assert_eq!(store.len().unwrap(), 0);
+    }
+}